@@ -0,0 +1,41 @@
+//! Async version of the driver for executors such as Embassy
+//!
+//! the API matches the blocking [`crate::IS31FL3731`], with every method that touches the bus
+//! being `async`. embedded-graphics can not wait on the bus, so drawing with it always goes to
+//! the frame buffer and is sent with `flush`
+
+use core::{borrow::BorrowMut, marker::PhantomData};
+
+use embedded_graphics_core::{
+    draw_target::DrawTarget,
+    pixelcolor::{Gray8, Rgb888, RgbColor},
+    prelude::{Dimensions, IntoStorage, OriginDimensions, Point, PointsIter, Size},
+    primitives::Rectangle,
+    Pixel,
+};
+use embedded_hal::i2c::ErrorType;
+use embedded_hal_async::{delay::DelayNs, i2c::I2c};
+
+use crate::{
+    check_frame,
+    mapping::{CharlieWing, PixelMapping, Rgb, RgbMapping},
+    steps, Address, AgcConfig, AudioPlay, AutoPlay, AutoPlayConfig, BlinkConfig, BreathConfig,
+    DisplayMode, Error, FrameBuffer, FrameState, Picture, Rotation, State, StorePixel,
+    ADC_RATE_STEP_US, ISSI_BANK_FUNCTIONREG, ISSI_COMMANDREGISTER, ISSI_REG_ADCRATE, ISSI_REG_AGC,
+    ISSI_REG_AUDIOSYNC, ISSI_REG_BREATHCTRL1, ISSI_REG_BREATHCTRL2, ISSI_REG_DISPLAYOPTION,
+    ISSI_REG_FRAMESTATE, ISSI_REG_FRAMESTATE_INT, ISSI_REG_LEDCTRL, ISSI_REG_PICTUREFRAME,
+    ISSI_REG_SHUTDOWN, RESTORED_FUNCTION_REGISTERS,
+};
+
+crate::driver::impl_driver!(async);
+
+impl<T, M, S, B> IS31FL3731<T, M, S, B>
+where
+    T: I2c,
+    B: BorrowMut<FrameBuffer>,
+{
+    /// drawing with embedded-graphics stays in the frame buffer, as it can not wait on the bus
+    fn send_drawn(&mut self) -> Result<(), Error<<T as ErrorType>::Error>> {
+        Ok(())
+    }
+}
@@ -0,0 +1,953 @@
+//! The driver and its builder, written once for the blocking and the async API
+//!
+//! `impl_driver!(blocking)` expands to plain methods and `impl_driver!(async)` to `async`
+//! ones that `.await` the bus. the I2c and DelayNs traits, and every other name used here,
+//! are the ones in scope where the macro is expanded
+
+macro_rules! impl_driver {
+    (blocking) => {
+        $crate::driver::impl_driver!(@items [] [] [dyn DelayNs]);
+    };
+    (async) => {
+        $crate::driver::impl_driver!(@items [async] [await] [impl DelayNs]);
+    };
+    (@items [$($async:tt)?] [$($await:tt)?] [$($delay:tt)*]) => {
+        /// The driver, with the display mode the chip is in as `S`: [`Picture`], [`AutoPlay`] or
+        /// [`AudioPlay`]
+        ///
+        /// `B` holds the [`FrameBuffer`], a copy of all 8 frames that lets the driver change single
+        /// LEDs, redraw after a reset and apply gamma or brightness, buffered or not
+        ///
+        /// the `into_` methods switch the display mode, if that fails they hand the driver back
+        /// along with the error
+        pub struct IS31FL3731<T, M = CharlieWing, S = Picture, B = FrameBuffer>
+        where
+            T: I2c,
+        {
+            address: u8,
+            i2c: T,
+            state: State<M, B>,
+            mode: PhantomData<S>,
+        }
+
+        impl<T> IS31FL3731<T, CharlieWing>
+        where
+            T: I2c,
+        {
+            /// reset the chip, clear all 8 frames and enable every LED of a CharliePlex FeatherWing
+            /// `i2c` can be a `&mut` borrow of the bus, to keep using it for other devices
+            pub $($async)? fn new(
+                i2c: T,
+                address: Address,
+                d: &mut $($delay)*,
+            ) -> Result<Self, Error<<T as ErrorType>::Error>> {
+                Self::new_with_mapping(i2c, address, CharlieWing, d)$(.$await)?
+            }
+
+            /// choose how the chip is set up, instead of the full reset done by new
+            pub fn builder(i2c: T, address: Address) -> Builder<T> {
+                IS31FL3731::builder_with_frame_buffer(i2c, address, FrameBuffer::new())
+            }
+        }
+
+        impl<T, B> IS31FL3731<T, CharlieWing, Picture, B>
+        where
+            T: I2c,
+            B: BorrowMut<FrameBuffer>,
+        {
+            /// like builder, keeping the frame buffer in `frames`, such as a `&mut` borrow of a
+            /// [`FrameBuffer`] in a `static`
+            pub fn builder_with_frame_buffer(
+                i2c: T,
+                address: Address,
+                frames: B,
+            ) -> Builder<T, CharlieWing, Picture, B> {
+                Builder {
+                    address: address.into(),
+                    i2c,
+                    mapping: CharlieWing,
+                    reset: true,
+                    clear_frames: 0xff,
+                    leds: Some([0xff; 18]),
+                    display_mode: DisplayMode::Picture(0),
+                    attach: false,
+                    frames,
+                    mode: PhantomData,
+                }
+            }
+        }
+
+        impl<T, M> IS31FL3731<T, M>
+        where
+            T: I2c,
+        {
+            /// like new, for boards wired differently from the CharliePlex FeatherWing
+            pub $($async)? fn new_with_mapping(
+                i2c: T,
+                address: Address,
+                mapping: M,
+                d: &mut $($delay)*,
+            ) -> Result<Self, Error<<T as ErrorType>::Error>> {
+                IS31FL3731::builder(i2c, address)
+                    .mapping(mapping)
+                    .build(d)
+                    $(.$await)?
+            }
+        }
+
+        impl<T, M, S, B> IS31FL3731<T, M, S, B>
+        where
+            T: I2c,
+            B: BorrowMut<FrameBuffer>,
+        {
+            pub fn select_frame(
+                &mut self,
+                frame: u8,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                self.state.select_frame(frame)
+            }
+
+            /// give the bus back, for example to hand it to another driver
+            pub fn release(self) -> T {
+                self.i2c
+            }
+
+            $($async)? fn write_to_bank(
+                &mut self,
+                bank: u8,
+                reg: u8,
+                value: u8,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                self.select_bank(bank)$(.$await)??;
+                self.i2c
+                    .write(self.address, &[reg, value])
+                    $(.$await)?
+                    .map_err(Error::I2c)?;
+                if bank == ISSI_BANK_FUNCTIONREG {
+                    self.state.remember_function(reg, value);
+                }
+                Ok(())
+            }
+
+            $($async)? fn select_bank(
+                &mut self,
+                bank: u8,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                if self.state.bank == Some(bank) {
+                    return Ok(());
+                }
+                // a failed write may or may not have reached the chip
+                self.state.bank = None;
+                self.i2c
+                    .write(self.address, &[ISSI_COMMANDREGISTER, bank])
+                    $(.$await)?
+                    .map_err(Error::I2c)?;
+                self.state.bank = Some(bank);
+                Ok(())
+            }
+
+            /// forget which register bank is selected, so the next write selects it again
+            /// call this after the chip was reset or the bus was disturbed behind the driver's back
+            pub fn invalidate_bank_cache(&mut self) {
+                self.state.bank = None;
+            }
+
+            /// enable each LED and turn them all off
+            /// disable blink as well
+            pub $($async)? fn clear(&mut self) -> Result<(), Error<<T as ErrorType>::Error>> {
+                let command = self.state.clear_command();
+
+                // select the current frame
+                self.select_bank(self.state.current_frame)$(.$await)??;
+                // send the command
+                self.i2c
+                    .write(self.address, &command)
+                    $(.$await)?
+                    .map_err(Error::I2c)
+            }
+
+            /// switch the chip to a display mode, or start an animation over with new settings
+            $($async)? fn start_mode(
+                &mut self,
+                mode: DisplayMode,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                for (reg, value) in mode.registers()?.into_iter().flatten() {
+                    self.write_to_bank(ISSI_BANK_FUNCTIONREG, reg, value)
+                        $(.$await)??;
+                }
+                Ok(())
+            }
+
+            /// switch the chip to another display mode, handing the driver back if that fails
+            $($async)? fn into_mode<N>(
+                mut self,
+                mode: DisplayMode,
+            ) -> Result<IS31FL3731<T, M, N, B>, (Self, Error<<T as ErrorType>::Error>)> {
+                if let Err(e) = self.start_mode(mode)$(.$await)? {
+                    return Err((self, e));
+                }
+                Ok(IS31FL3731 {
+                    address: self.address,
+                    i2c: self.i2c,
+                    state: self.state,
+                    mode: PhantomData,
+                })
+            }
+
+            pub $($async)? fn set_breath(
+                &mut self,
+                config: &BreathConfig,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                let [control1, control2] = config.registers()?;
+                self.write_to_bank(ISSI_BANK_FUNCTIONREG, ISSI_REG_BREATHCTRL1, control1)
+                    $(.$await)??;
+                self.write_to_bank(ISSI_BANK_FUNCTIONREG, ISSI_REG_BREATHCTRL2, control2)
+                    $(.$await)?
+            }
+
+            pub $($async)? fn set_blink(
+                &mut self,
+                config: &BlinkConfig,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                self.write_to_bank(
+                    ISSI_BANK_FUNCTIONREG,
+                    ISSI_REG_DISPLAYOPTION,
+                    config.register()?,
+                )
+                $(.$await)?
+            }
+
+            /// modulate the brightness of the display with the audio input
+            pub $($async)? fn set_audio_sync(
+                &mut self,
+                enabled: bool,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                self.write_to_bank(ISSI_BANK_FUNCTIONREG, ISSI_REG_AUDIOSYNC, enabled as u8)
+                    $(.$await)?
+            }
+
+            pub $($async)? fn set_agc(
+                &mut self,
+                config: &AgcConfig,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                self.write_to_bank(ISSI_BANK_FUNCTIONREG, ISSI_REG_AGC, config.register()?)
+                    $(.$await)?
+            }
+
+            /// set how often the audio input is sampled
+            /// the period is rounded to the nearest 46us step (46 - 11776us)
+            pub $($async)? fn set_audio_sample_period(
+                &mut self,
+                period_us: u16,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                // a period of 256 steps is written as 0
+                let rate = steps(period_us, ADC_RATE_STEP_US, 256)? as u8;
+                self.write_to_bank(ISSI_BANK_FUNCTIONREG, ISSI_REG_ADCRATE, rate)
+                    $(.$await)?
+            }
+
+            /// turn the display off to save power, the chip keeps every register and frame
+            pub $($async)? fn shutdown(&mut self) -> Result<(), Error<<T as ErrorType>::Error>> {
+                self.write_to_bank(ISSI_BANK_FUNCTIONREG, ISSI_REG_SHUTDOWN, 0x00)
+                    $(.$await)?
+            }
+
+            /// turn the display back on after shutdown
+            pub $($async)? fn wake(&mut self) -> Result<(), Error<<T as ErrorType>::Error>> {
+                self.write_to_bank(ISSI_BANK_FUNCTIONREG, ISSI_REG_SHUTDOWN, 0x01)
+                    $(.$await)?
+            }
+
+            /// restart the chip, then send the driver's configuration, the LED enable and blink
+            /// bits and everything the driver drew again, for example after the chip lost power
+            /// anything still in the frame buffer is sent as well, PWM values the driver never
+            /// drew and function registers it never wrote are left alone
+            pub $($async)? fn reset(
+                &mut self,
+                d: &mut $($delay)*,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                self.invalidate_bank_cache();
+                self.shutdown()$(.$await)??;
+                d.delay_ms(10)$(.$await)?;
+                self.wake()$(.$await)??;
+
+                self.state.redraw_owned();
+                for frame in 0..8u8 {
+                    let command = self.state.masks_command(frame);
+                    self.select_bank(frame)$(.$await)??;
+                    self.i2c
+                        .write(self.address, &command)
+                        $(.$await)?
+                        .map_err(Error::I2c)?;
+                    self.flush_frame(frame)$(.$await)??;
+                }
+                for reg in RESTORED_FUNCTION_REGISTERS {
+                    if self.state.function_known & (1 << reg) != 0 {
+                        let value = self.state.function[reg as usize];
+                        self.write_to_bank(ISSI_BANK_FUNCTIONREG, reg, value)
+                            $(.$await)??;
+                    }
+                }
+                Ok(())
+            }
+
+            /// with buffering on, drawing only changes the frame buffer in RAM until flush is
+            /// called
+            /// with it off, every pixel is sent to the chip as soon as it is drawn
+            /// the frame buffer takes the same RAM either way, turning buffering off does not save
+            /// any
+            pub fn set_buffered(&mut self, buffered: bool) {
+                self.state.buffered = buffered;
+            }
+
+            /// send every PWM value through a brightness curve, such as [`crate::gamma::GAMMA_2_2`]
+            /// what the driver drew or cleared before the change is sent again on the next flush,
+            /// frames it never touched are left alone
+            pub fn set_gamma(&mut self, gamma: Option<&'static [u8; 256]>) {
+                self.state.gamma = gamma;
+                self.state.redraw_owned();
+            }
+
+            /// dim the whole display without drawing it again, 255 is full brightness
+            /// the current frame is sent again at the new level unless drawing is buffered,
+            /// other frames are sent again on the next flush
+            /// only what the driver drew or cleared is sent, frames it never touched are left alone
+            pub $($async)? fn set_brightness(
+                &mut self,
+                brightness: u8,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                self.state.brightness = brightness;
+                self.state.redraw_owned();
+                if self.state.buffered {
+                    return Ok(());
+                }
+                self.flush_frame(self.state.current_frame)$(.$await)?
+            }
+
+            /// rotate everything drawn from now on, the size of the display swaps for 90 and 270
+            /// degrees
+            pub fn set_rotation(&mut self, rotation: Rotation) {
+                self.state.rotation = rotation;
+            }
+
+            /// flip everything drawn from now on, left to right and top to bottom as seen after
+            /// rotation
+            pub fn set_mirror(&mut self, horizontal: bool, vertical: bool) {
+                self.state.mirror_x = horizontal;
+                self.state.mirror_y = vertical;
+            }
+
+            /// send everything drawn into the frame buffers since the last flush
+            pub $($async)? fn flush(&mut self) -> Result<(), Error<<T as ErrorType>::Error>> {
+                for frame in 0..8u8 {
+                    self.flush_frame(frame)$(.$await)??;
+                }
+                Ok(())
+            }
+
+            /// send the changed parts of a frame buffer, in as few bursts as possible
+            $($async)? fn flush_frame(
+                &mut self,
+                frame: u8,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                let mut from = 0;
+                while let Some(leds) = self.state.next_dirty_run(frame, from) {
+                    self.select_bank(frame)$(.$await)??;
+                    from = leds.end;
+                    let (command, len) = self.state.pwm_command(frame, leds);
+                    self.i2c
+                        .write(self.address, &command[..len])
+                        $(.$await)?
+                        .map_err(Error::I2c)?;
+                }
+                self.state.flushed(frame);
+                Ok(())
+            }
+
+            pub $($async)? fn fill(&mut self, c: u8) -> Result<(), Error<<T as ErrorType>::Error>> {
+                self.state.fill_pwm(c);
+                if self.state.buffered {
+                    return Ok(());
+                }
+                self.flush_frame(self.state.current_frame)$(.$await)?
+            }
+
+            /// read the LED enable and blink bits of a frame, which single LED changes are based on
+            $($async)? fn read_masks(
+                &mut self,
+                frame: u8,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                let mut masks = [0u8; 36];
+                self.read_from_bank(frame, ISSI_REG_LEDCTRL, &mut masks)
+                    $(.$await)??;
+                self.state.set_masks(frame, &masks);
+                Ok(())
+            }
+
+            $($async)? fn read_from_bank(
+                &mut self,
+                bank: u8,
+                reg: u8,
+                buffer: &mut [u8],
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                self.select_bank(bank)$(.$await)??;
+                self.i2c
+                    .write_read(self.address, &[reg], buffer)
+                    $(.$await)?
+                    .map_err(Error::I2c)
+            }
+
+            pub $($async)? fn frame_state(
+                &mut self,
+            ) -> Result<FrameState, Error<<T as ErrorType>::Error>> {
+                let mut state = [0u8];
+                self.read_from_bank(ISSI_BANK_FUNCTIONREG, ISSI_REG_FRAMESTATE, &mut state)
+                    $(.$await)??;
+                Ok(FrameState {
+                    current_frame: state[0] & 0x07,
+                    interrupt: state[0] & ISSI_REG_FRAMESTATE_INT != 0,
+                })
+            }
+
+            /// read the PWM values of every LED in the current frame, indexed by LED number
+            pub $($async)? fn read_pwm(
+                &mut self,
+                pwm: &mut [u8; 144],
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                self.read_from_bank(self.state.current_frame, 0x24, pwm)
+                    $(.$await)?
+            }
+
+            /// send every LED enable bit of the current frame, for changes that can be spread over
+            /// any of its LED control registers
+            $($async)? fn send_leds(&mut self) -> Result<(), Error<<T as ErrorType>::Error>> {
+                let command = self.state.leds_command();
+                self.select_bank(self.state.current_frame)$(.$await)??;
+                self.i2c
+                    .write(self.address, &command)
+                    $(.$await)?
+                    .map_err(Error::I2c)
+            }
+
+            /// read the LED enable bits of the current frame, one bit per LED number
+            pub $($async)? fn read_led_control(
+                &mut self,
+            ) -> Result<[u8; 18], Error<<T as ErrorType>::Error>> {
+                let mut leds = [0u8; 18];
+                self.read_from_bank(self.state.current_frame, ISSI_REG_LEDCTRL, &mut leds)
+                    $(.$await)??;
+                Ok(leds)
+            }
+        }
+
+        impl<T, M, B> IS31FL3731<T, M, Picture, B>
+        where
+            T: I2c,
+            B: BorrowMut<FrameBuffer>,
+        {
+            pub $($async)? fn display_frame(
+                &mut self,
+                frame: u8,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                check_frame(frame)?;
+                self.write_to_bank(ISSI_BANK_FUNCTIONREG, ISSI_REG_PICTUREFRAME, frame)
+                    $(.$await)?
+            }
+
+            /// play frames in a loop without any help from the MCU
+            pub $($async)? fn into_auto_play(
+                self,
+                config: &AutoPlayConfig,
+            ) -> Result<IS31FL3731<T, M, AutoPlay, B>, (Self, Error<<T as ErrorType>::Error>)> {
+                self.into_mode(DisplayMode::AutoPlay(*config))$(.$await)?
+            }
+
+            /// pick the displayed frame from the level of the audio input
+            pub $($async)? fn into_audio_play(
+                self,
+            ) -> Result<IS31FL3731<T, M, AudioPlay, B>, (Self, Error<<T as ErrorType>::Error>)> {
+                self.into_mode(DisplayMode::AudioPlay)$(.$await)?
+            }
+        }
+
+        impl<T, M, B> IS31FL3731<T, M, AutoPlay, B>
+        where
+            T: I2c,
+            B: BorrowMut<FrameBuffer>,
+        {
+            /// change the frames, loops and delay of the animation, which starts over
+            pub $($async)? fn set_auto_play(
+                &mut self,
+                config: &AutoPlayConfig,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                self.start_mode(DisplayMode::AutoPlay(*config))$(.$await)?
+            }
+
+            /// stop the animation and show a single frame
+            pub $($async)? fn into_picture(
+                self,
+                frame: u8,
+            ) -> Result<IS31FL3731<T, M, Picture, B>, (Self, Error<<T as ErrorType>::Error>)> {
+                self.into_mode(DisplayMode::Picture(frame))$(.$await)?
+            }
+
+            /// pick the displayed frame from the level of the audio input
+            pub $($async)? fn into_audio_play(
+                self,
+            ) -> Result<IS31FL3731<T, M, AudioPlay, B>, (Self, Error<<T as ErrorType>::Error>)> {
+                self.into_mode(DisplayMode::AudioPlay)$(.$await)?
+            }
+        }
+
+        impl<T, M, B> IS31FL3731<T, M, AudioPlay, B>
+        where
+            T: I2c,
+            B: BorrowMut<FrameBuffer>,
+        {
+            /// stop following the audio input and show a single frame
+            pub $($async)? fn into_picture(
+                self,
+                frame: u8,
+            ) -> Result<IS31FL3731<T, M, Picture, B>, (Self, Error<<T as ErrorType>::Error>)> {
+                self.into_mode(DisplayMode::Picture(frame))$(.$await)?
+            }
+
+            /// play frames in a loop without any help from the MCU
+            pub $($async)? fn into_auto_play(
+                self,
+                config: &AutoPlayConfig,
+            ) -> Result<IS31FL3731<T, M, AutoPlay, B>, (Self, Error<<T as ErrorType>::Error>)> {
+                self.into_mode(DisplayMode::AutoPlay(*config))$(.$await)?
+            }
+        }
+
+        impl<T, M, S, B> IS31FL3731<T, M, S, B>
+        where
+            T: I2c,
+            B: BorrowMut<FrameBuffer>,
+            M: PixelMapping,
+        {
+            /// set the brightness of a pixel, [`Error::OutOfBounds`] if it is off the display
+            pub $($async)? fn draw_pixel(
+                &mut self,
+                x: i16,
+                y: i16,
+                c: u8,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                let pixel_num = self.state.led_index(x, y)?;
+                self.state.set_pwm(pixel_num, c);
+                if self.state.buffered {
+                    return Ok(());
+                }
+                self.flush_frame(self.state.current_frame)$(.$await)?
+            }
+
+            /// make a pixel of the current frame blink while blinking is enabled with set_blink
+            pub $($async)? fn set_pixel_blink(
+                &mut self,
+                x: i16,
+                y: i16,
+                blink: bool,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                let pixel_num = self.state.led_index(x, y)?;
+                let (reg, value) = self.state.set_blink(pixel_num, blink);
+                self.write_to_bank(self.state.current_frame, reg, value)
+                    $(.$await)?
+            }
+
+            /// turn a pixel of the current frame on or off in hardware, independent of its PWM
+            /// value
+            pub $($async)? fn set_led_enabled(
+                &mut self,
+                x: i16,
+                y: i16,
+                enabled: bool,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                let pixel_num = self.state.led_index(x, y)?;
+                let (reg, value) = self.state.set_led_enabled(pixel_num, enabled);
+                self.write_to_bank(self.state.current_frame, reg, value)
+                    $(.$await)?
+            }
+
+            /// turn every pixel in a row of the current frame on or off in hardware
+            pub $($async)? fn set_row_enabled(
+                &mut self,
+                y: i16,
+                enabled: bool,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                self.state.set_row_enabled(y, enabled)?;
+                self.send_leds()$(.$await)?
+            }
+
+            /// read the PWM value of a pixel in the current frame
+            pub $($async)? fn read_pixel(
+                &mut self,
+                x: i16,
+                y: i16,
+            ) -> Result<u8, Error<<T as ErrorType>::Error>> {
+                let pixel_num = self.state.led_index(x, y)?;
+                let mut value = [0u8];
+                self.read_from_bank(self.state.current_frame, 0x24 + pixel_num, &mut value)
+                    $(.$await)??;
+                Ok(value[0])
+            }
+        }
+
+        impl<T, R, S, B> IS31FL3731<T, Rgb<R>, S, B>
+        where
+            T: I2c,
+            B: BorrowMut<FrameBuffer>,
+            R: RgbMapping,
+        {
+            /// set the color of a pixel, [`Error::OutOfBounds`] if it is off the display
+            pub $($async)? fn draw_pixel(
+                &mut self,
+                x: i16,
+                y: i16,
+                c: Rgb888,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                let [r, g, b] = self.state.led_indices(x, y)?;
+                self.state.set_pwm(r, c.r());
+                self.state.set_pwm(g, c.g());
+                self.state.set_pwm(b, c.b());
+                if self.state.buffered {
+                    return Ok(());
+                }
+                self.flush_frame(self.state.current_frame)$(.$await)?
+            }
+
+            /// make a pixel of the current frame blink while blinking is enabled with set_blink
+            pub $($async)? fn set_pixel_blink(
+                &mut self,
+                x: i16,
+                y: i16,
+                blink: bool,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                for pixel_num in self.state.led_indices(x, y)? {
+                    let (reg, value) = self.state.set_blink(pixel_num, blink);
+                    self.write_to_bank(self.state.current_frame, reg, value)
+                        $(.$await)??;
+                }
+                Ok(())
+            }
+
+            /// turn a pixel of the current frame on or off in hardware, independent of its PWM
+            /// value
+            pub $($async)? fn set_led_enabled(
+                &mut self,
+                x: i16,
+                y: i16,
+                enabled: bool,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                for pixel_num in self.state.led_indices(x, y)? {
+                    let (reg, value) = self.state.set_led_enabled(pixel_num, enabled);
+                    self.write_to_bank(self.state.current_frame, reg, value)
+                        $(.$await)??;
+                }
+                Ok(())
+            }
+
+            /// turn every pixel in a row of the current frame on or off in hardware
+            pub $($async)? fn set_row_enabled(
+                &mut self,
+                y: i16,
+                enabled: bool,
+            ) -> Result<(), Error<<T as ErrorType>::Error>> {
+                self.state.set_row_enabled(y, enabled)?;
+                self.send_leds()$(.$await)?
+            }
+
+            /// read the PWM values of a pixel in the current frame
+            pub $($async)? fn read_pixel(
+                &mut self,
+                x: i16,
+                y: i16,
+            ) -> Result<Rgb888, Error<<T as ErrorType>::Error>> {
+                let mut channels = [0u8; 3];
+                for (channel, pixel_num) in channels.iter_mut().zip(self.state.led_indices(x, y)?) {
+                    let mut value = [0u8];
+                    self.read_from_bank(self.state.current_frame, 0x24 + pixel_num, &mut value)
+                        $(.$await)??;
+                    *channel = value[0];
+                }
+                let [r, g, b] = channels;
+                Ok(Rgb888::new(r, g, b))
+            }
+        }
+
+        /// Sets up the chip step by step, see [`IS31FL3731::builder`]
+        ///
+        /// by default it does what new does: reset the chip, clear all 8 frames, enable every LED,
+        /// turn audio sync off and show frame 0 in Picture Mode
+        pub struct Builder<T, M = CharlieWing, S = Picture, B = FrameBuffer> {
+            address: u8,
+            i2c: T,
+            mapping: M,
+            reset: bool,
+            clear_frames: u8,
+            leds: Option<[u8; 18]>,
+            display_mode: DisplayMode,
+            attach: bool,
+            frames: B,
+            mode: PhantomData<S>,
+        }
+
+        impl<T, M, S, B> Builder<T, M, S, B>
+        where
+            T: I2c,
+            B: BorrowMut<FrameBuffer>,
+        {
+            /// for boards wired differently from the CharliePlex FeatherWing
+            pub fn mapping<N>(self, mapping: N) -> Builder<T, N, S, B> {
+                Builder {
+                    address: self.address,
+                    i2c: self.i2c,
+                    mapping,
+                    reset: self.reset,
+                    clear_frames: self.clear_frames,
+                    leds: self.leds,
+                    display_mode: self.display_mode,
+                    attach: self.attach,
+                    frames: self.frames,
+                    mode: PhantomData,
+                }
+            }
+
+            /// the same settings with another display mode
+            fn with_display_mode<R>(self, display_mode: DisplayMode) -> Builder<T, M, R, B> {
+                Builder {
+                    address: self.address,
+                    i2c: self.i2c,
+                    mapping: self.mapping,
+                    reset: self.reset,
+                    clear_frames: self.clear_frames,
+                    leds: self.leds,
+                    display_mode,
+                    attach: self.attach,
+                    frames: self.frames,
+                    mode: PhantomData,
+                }
+            }
+
+            /// restart the chip with a shutdown pulse first
+            pub fn reset(mut self, reset: bool) -> Self {
+                self.reset = reset;
+                self
+            }
+
+            /// frames to clear like [`IS31FL3731::clear`] does, bit n stands for frame n
+            /// frames that are not cleared keep whatever the chip shows, their LED enable and blink
+            /// bits are read back so changing single LEDs leaves the others alone, which a bus that
+            /// can only write can not do
+            pub fn clear_frames(mut self, frames: u8) -> Self {
+                self.clear_frames = frames;
+                self
+            }
+
+            /// LED enable bits to write to all 8 frames, `None` leaves them as they are
+            pub fn led_mask(mut self, leds: Option<[u8; 18]>) -> Self {
+                self.leds = leds;
+                self
+            }
+
+            /// end up in Picture Mode, showing a frame
+            pub fn picture(self, frame: u8) -> Builder<T, M, Picture, B> {
+                self.with_display_mode(DisplayMode::Picture(frame))
+            }
+
+            /// end up in Auto Frame Play mode
+            pub fn auto_play(self, config: AutoPlayConfig) -> Builder<T, M, AutoPlay, B> {
+                self.with_display_mode(DisplayMode::AutoPlay(config))
+            }
+
+            /// end up in Audio Frame Play mode
+            pub fn audio_play(self) -> Builder<T, M, AudioPlay, B> {
+                self.with_display_mode(DisplayMode::AudioPlay)
+            }
+
+            /// take over a chip that is already configured without changing what it shows, the LED
+            /// enable and blink bits of each frame are read back. that writes the command register
+            /// to select each frame and the address of the register to read, nothing else
+            /// every other setting of the builder is ignored, except for the display mode, which
+            /// has to be the one the chip is in
+            pub fn attach(mut self, attach: bool) -> Self {
+                self.attach = attach;
+                self
+            }
+
+            pub $($async)? fn build(
+                self,
+                d: &mut $($delay)*,
+            ) -> Result<IS31FL3731<T, M, S, B>, Error<<T as ErrorType>::Error>> {
+                // a display mode the chip can not show fails before anything is sent
+                let mode_registers = self.display_mode.registers()?;
+                let mut dev = IS31FL3731 {
+                    address: self.address,
+                    i2c: self.i2c,
+                    state: State::new(self.mapping, self.frames),
+                    mode: PhantomData,
+                };
+                if self.attach {
+                    for frame in 0..8u8 {
+                        dev.read_masks(frame)$(.$await)??;
+                    }
+                    // reset restores the display mode the driver was told the chip is in
+                    for (reg, value) in mode_registers.into_iter().flatten() {
+                        dev.state.remember_function(reg, value);
+                    }
+                    return Ok(dev);
+                }
+
+                if self.reset {
+                    dev.shutdown()$(.$await)??;
+                    d.delay_ms(10)$(.$await)?;
+                    dev.wake()$(.$await)??;
+                }
+
+                for frame in 0..8u8 {
+                    if self.clear_frames & (1 << frame) != 0 {
+                        dev.state.current_frame = frame;
+                        dev.clear()$(.$await)??;
+                    } else {
+                        dev.read_masks(frame)$(.$await)??;
+                    }
+                }
+                if let Some(leds) = self.leds {
+                    for frame in 0..8u8 {
+                        dev.state.current_frame = frame;
+                        dev.state.set_leds(leds);
+                        dev.send_leds()$(.$await)??;
+                    }
+                }
+                dev.state.current_frame = 0;
+
+                // disable audio sync
+                dev.write_to_bank(ISSI_BANK_FUNCTIONREG, ISSI_REG_AUDIOSYNC, 0x0)
+                    $(.$await)??;
+
+                dev.start_mode(self.display_mode)$(.$await)??;
+                Ok(dev)
+            }
+        }
+
+        impl<T, M, S, B> IS31FL3731<T, M, S, B>
+        where
+            T: I2c,
+            B: BorrowMut<FrameBuffer>,
+        {
+            /// store pixels drawn with embedded-graphics and send them, pixels off the display are
+            /// clipped, as embedded-graphics expects
+            fn draw_pixels<C>(
+                &mut self,
+                pixels: impl IntoIterator<Item = (Point, C)>,
+            ) -> Result<(), Error<<T as ErrorType>::Error>>
+            where
+                State<M, B>: StorePixel<Color = C>,
+            {
+                for (point, color) in pixels {
+                    self.state.store_pixel(point, color)?;
+                }
+                self.send_drawn()
+            }
+        }
+
+        /// draw in shades of gray
+        impl<T, M, S, B> DrawTarget for IS31FL3731<T, M, S, B>
+        where
+            T: I2c,
+            B: BorrowMut<FrameBuffer>,
+            M: PixelMapping,
+        {
+            type Color = Gray8;
+            type Error = Error<<T as ErrorType>::Error>;
+            fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+            where
+                I: IntoIterator<Item = Pixel<Self::Color>>,
+            {
+                self.draw_pixels(pixels.into_iter().map(|Pixel(point, color)| (point, color)))
+            }
+
+            fn fill_solid(
+                &mut self,
+                area: &Rectangle,
+                color: Self::Color,
+            ) -> Result<(), Self::Error> {
+                let area = area.intersection(&self.bounding_box());
+                self.draw_pixels(area.points().map(|point| (point, color)))
+            }
+
+            fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+            where
+                I: IntoIterator<Item = Self::Color>,
+            {
+                self.draw_pixels(area.points().zip(colors))
+            }
+
+            fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+                self.state.fill_pwm(color.into_storage());
+                self.send_drawn()
+            }
+        }
+
+        impl<T, M, S, B> OriginDimensions for IS31FL3731<T, M, S, B>
+        where
+            T: I2c,
+            B: BorrowMut<FrameBuffer>,
+            M: PixelMapping,
+        {
+            fn size(&self) -> Size {
+                self.state.size()
+            }
+        }
+
+        /// draw in color on an RGB display
+        impl<T, R, S, B> DrawTarget for IS31FL3731<T, Rgb<R>, S, B>
+        where
+            T: I2c,
+            B: BorrowMut<FrameBuffer>,
+            R: RgbMapping,
+        {
+            type Color = Rgb888;
+            type Error = Error<<T as ErrorType>::Error>;
+            fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+            where
+                I: IntoIterator<Item = Pixel<Self::Color>>,
+            {
+                self.draw_pixels(pixels.into_iter().map(|Pixel(point, color)| (point, color)))
+            }
+
+            fn fill_solid(
+                &mut self,
+                area: &Rectangle,
+                color: Self::Color,
+            ) -> Result<(), Self::Error> {
+                let area = area.intersection(&self.bounding_box());
+                self.draw_pixels(area.points().map(|point| (point, color)))
+            }
+
+            fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+            where
+                I: IntoIterator<Item = Self::Color>,
+            {
+                self.draw_pixels(area.points().zip(colors))
+            }
+
+            fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+                self.fill_solid(&self.bounding_box(), color)
+            }
+        }
+
+        impl<T, R, S, B> OriginDimensions for IS31FL3731<T, Rgb<R>, S, B>
+        where
+            T: I2c,
+            B: BorrowMut<FrameBuffer>,
+            R: RgbMapping,
+        {
+            fn size(&self) -> Size {
+                self.state.size()
+            }
+        }
+    };
+}
+
+pub(crate) use impl_driver;
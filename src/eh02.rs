@@ -0,0 +1,314 @@
+//! Adapters for peripherals that only implement the embedded-hal 0.2 traits
+//!
+//! wrap the bus in [`Eh02I2c`] and the delay in [`Eh02Delay`] before handing them to the driver.
+//! a bus that can only write goes in [`Eh02WriteOnlyI2c`] instead
+
+use core::fmt::Debug;
+
+use embedded_hal::{
+    delay::DelayNs,
+    i2c::{self, ErrorKind, ErrorType, I2c, Operation, SevenBitAddress},
+};
+use embedded_hal_02::blocking::{
+    delay::DelayUs,
+    i2c::{Read, Write, WriteRead},
+};
+
+/// longest run of writes a transaction can join into one, more than the driver ever sends
+const MERGED_LEN: usize = 256;
+
+/// join the writes a transaction starts with into `buffer`, the way embedded-hal 1.0 sends
+/// adjacent writes without a new start condition, and return their length with the rest
+fn merge_writes<'a, 'b, E>(
+    operations: &'a mut [Operation<'b>],
+    buffer: &mut [u8; MERGED_LEN],
+) -> Result<(usize, &'a mut [Operation<'b>]), Eh02Error<E>> {
+    let mut len = 0;
+    let mut rest = operations;
+    while let [Operation::Write(write), tail @ ..] = rest {
+        buffer
+            .get_mut(len..len + write.len())
+            .ok_or(Eh02Error::Unsupported)?
+            .copy_from_slice(write);
+        len += write.len();
+        rest = tail;
+    }
+    Ok((len, rest))
+}
+
+/// An embedded-hal 0.2 I2C bus usable as an embedded-hal 1.0 one
+pub struct Eh02I2c<T>(pub T);
+
+/// An embedded-hal 0.2 I2C bus that can only write, usable as an embedded-hal 1.0 one
+///
+/// reads fail with [`Eh02Error::Unsupported`], so the driver can be set up with new or the
+/// builder, but not attached to a chip or told to keep frames that it would have to read back
+pub struct Eh02WriteOnlyI2c<T>(pub T);
+
+/// An error from an embedded-hal 0.2 I2C bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eh02Error<E> {
+    /// the bus returned an error
+    Bus(E),
+    /// the bus has no way to do what was asked
+    Unsupported,
+}
+
+impl<E: Debug> i2c::Error for Eh02Error<E> {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+impl<T, E> ErrorType for Eh02I2c<T>
+where
+    T: Write<Error = E> + Read<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    type Error = Eh02Error<E>;
+}
+
+impl<T, E> I2c<SevenBitAddress> for Eh02I2c<T>
+where
+    T: Write<Error = E> + Read<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read(address, read).map_err(Eh02Error::Bus)
+    }
+
+    fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+        self.0.write(address, write).map_err(Eh02Error::Bus)
+    }
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.0
+            .write_read(address, write, read)
+            .map_err(Eh02Error::Bus)
+    }
+
+    /// writes, then at most one read, are sent as one transaction, anything else fails with
+    /// [`Eh02Error::Unsupported`] as embedded-hal 0.2 has no way to send it
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        match operations {
+            [] => return Ok(()),
+            [Operation::Write(write)] => return self.write(address, write),
+            [Operation::Read(read)] => return self.read(address, read),
+            [Operation::Write(write), Operation::Read(read)] => {
+                return self.write_read(address, write, read)
+            }
+            _ => {}
+        }
+        let mut buffer = [0; MERGED_LEN];
+        let (len, rest) = merge_writes(operations, &mut buffer)?;
+        match rest {
+            [] => self.write(address, &buffer[..len]),
+            [Operation::Read(read)] => self.write_read(address, &buffer[..len], read),
+            _ => Err(Eh02Error::Unsupported),
+        }
+    }
+}
+
+impl<T, E> ErrorType for Eh02WriteOnlyI2c<T>
+where
+    T: Write<Error = E>,
+    E: Debug,
+{
+    type Error = Eh02Error<E>;
+}
+
+impl<T, E> I2c<SevenBitAddress> for Eh02WriteOnlyI2c<T>
+where
+    T: Write<Error = E>,
+    E: Debug,
+{
+    fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+        self.0.write(address, write).map_err(Eh02Error::Bus)
+    }
+
+    /// writes are sent as one transaction, a read fails with [`Eh02Error::Unsupported`]
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        match operations {
+            [] => return Ok(()),
+            [Operation::Write(write)] => return self.write(address, write),
+            _ => {}
+        }
+        let mut buffer = [0; MERGED_LEN];
+        match merge_writes(operations, &mut buffer)? {
+            (len, []) => self.write(address, &buffer[..len]),
+            _ => Err(Eh02Error::Unsupported),
+        }
+    }
+}
+
+/// An embedded-hal 0.2 delay usable as an embedded-hal 1.0 one
+pub struct Eh02Delay<D>(pub D);
+
+impl<D: DelayUs<u32>> DelayNs for Eh02Delay<D> {
+    fn delay_ns(&mut self, ns: u32) {
+        self.0.delay_us(ns.div_ceil(1000));
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.0.delay_us(us);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use super::*;
+
+    /// an embedded-hal 0.2 bus that records what it was asked to send, reads give 0xAA
+    #[derive(Default)]
+    struct Bus(Vec<(&'static str, Vec<u8>)>);
+
+    impl Write for Bus {
+        type Error = ();
+
+        fn write(&mut self, _address: u8, bytes: &[u8]) -> Result<(), ()> {
+            self.0.push(("write", bytes.to_vec()));
+            Ok(())
+        }
+    }
+
+    impl Read for Bus {
+        type Error = ();
+
+        fn read(&mut self, _address: u8, buffer: &mut [u8]) -> Result<(), ()> {
+            buffer.fill(0xAA);
+            self.0.push(("read", vec![]));
+            Ok(())
+        }
+    }
+
+    impl WriteRead for Bus {
+        type Error = ();
+
+        fn write_read(&mut self, _address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), ()> {
+            buffer.fill(0xAA);
+            self.0.push(("write_read", bytes.to_vec()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn merge_writes_stops_at_the_first_read() {
+        let mut buffer = [0; MERGED_LEN];
+        let mut read = [0; 2];
+        let mut operations = [
+            Operation::Write(&[1, 2]),
+            Operation::Write(&[3]),
+            Operation::Read(&mut read),
+            Operation::Write(&[4]),
+        ];
+        let (len, rest) = merge_writes::<()>(&mut operations, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], [1, 2, 3]);
+        assert!(matches!(rest, [Operation::Read(_), Operation::Write(_)]));
+
+        let (len, rest) = merge_writes::<()>(&mut [], &mut buffer).unwrap();
+        assert_eq!((len, rest.len()), (0, 0));
+
+        let long = [0; MERGED_LEN];
+        let mut operations = [Operation::Write(&long), Operation::Write(&[1])];
+        assert_eq!(
+            merge_writes::<()>(&mut operations, &mut buffer).err(),
+            Some(Eh02Error::Unsupported)
+        );
+    }
+
+    #[test]
+    fn writes_are_joined() {
+        let mut i2c = Eh02I2c(Bus::default());
+        i2c.transaction(
+            0x74,
+            &mut [Operation::Write(&[1]), Operation::Write(&[2, 3])],
+        )
+        .unwrap();
+        assert_eq!(i2c.0 .0, [("write", vec![1, 2, 3])]);
+
+        let mut i2c = Eh02WriteOnlyI2c(Bus::default());
+        i2c.transaction(
+            0x74,
+            &mut [Operation::Write(&[1]), Operation::Write(&[2, 3])],
+        )
+        .unwrap();
+        assert_eq!(i2c.0 .0, [("write", vec![1, 2, 3])]);
+    }
+
+    #[test]
+    fn writes_then_a_read_are_one_write_read() {
+        let mut i2c = Eh02I2c(Bus::default());
+        let mut read = [0; 2];
+        i2c.transaction(
+            0x74,
+            &mut [
+                Operation::Write(&[1]),
+                Operation::Write(&[2]),
+                Operation::Read(&mut read),
+            ],
+        )
+        .unwrap();
+        assert_eq!(i2c.0 .0, [("write_read", vec![1, 2])]);
+        assert_eq!(read, [0xAA; 2]);
+    }
+
+    #[test]
+    fn empty_transaction_sends_nothing() {
+        let mut i2c = Eh02I2c(Bus::default());
+        i2c.transaction(0x74, &mut []).unwrap();
+        assert!(i2c.0 .0.is_empty());
+
+        let mut i2c = Eh02WriteOnlyI2c(Bus::default());
+        i2c.transaction(0x74, &mut []).unwrap();
+        assert!(i2c.0 .0.is_empty());
+    }
+
+    #[test]
+    fn unsendable_transactions_are_unsupported() {
+        let long = [0; MERGED_LEN];
+        let [mut a, mut b, mut c] = [[0; 1]; 3];
+        let mut i2c = Eh02I2c(Bus::default());
+        for operations in [
+            &mut [Operation::Read(&mut a), Operation::Write(&[1])][..],
+            &mut [Operation::Read(&mut b), Operation::Read(&mut c)],
+            &mut [Operation::Write(&long), Operation::Write(&[1])],
+        ] {
+            assert_eq!(
+                i2c.transaction(0x74, operations),
+                Err(Eh02Error::Unsupported)
+            );
+        }
+        assert!(i2c.0 .0.is_empty());
+
+        let mut i2c = Eh02WriteOnlyI2c(Bus::default());
+        let [mut a, mut b] = [[0; 1]; 2];
+        for operations in [
+            &mut [Operation::Write(&[1]), Operation::Read(&mut a)][..],
+            &mut [Operation::Read(&mut b)],
+            &mut [Operation::Write(&long), Operation::Write(&[1])],
+        ] {
+            assert_eq!(
+                i2c.transaction(0x74, operations),
+                Err(Eh02Error::Unsupported)
+            );
+        }
+        assert!(i2c.0 .0.is_empty());
+    }
+}
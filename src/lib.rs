@@ -1,67 +1,513 @@
 #![no_std]
 
-use core::result::Result;
+use core::{borrow::BorrowMut, marker::PhantomData, ops::Range, result::Result};
 
 use embedded_graphics_core::{
     draw_target::DrawTarget,
-    pixelcolor::Gray8,
+    pixelcolor::{Gray8, Rgb888, RgbColor},
     prelude::OriginDimensions,
-    prelude::{IntoStorage, Size},
+    prelude::{Dimensions, IntoStorage, Point, PointsIter, Size},
+    primitives::Rectangle,
     Pixel,
 };
-use embedded_hal::blocking::{
-    delay::DelayMs,
-    i2c::{AddressMode, Write},
+use embedded_hal::{
+    delay::DelayNs,
+    i2c::{ErrorType, I2c, SevenBitAddress},
 };
 
+#[cfg(feature = "async")]
+pub mod asynch;
+mod driver;
+#[cfg(feature = "eh02")]
+pub mod eh02;
+pub mod gamma;
+pub mod mapping;
+
+use mapping::{CharlieWing, PixelMapping, Rgb, RgbMapping};
+
+const ISSI_REG_CONFIG: u8 = 0x00;
+const ISSI_REG_CONFIG_PICTUREMODE: u8 = 0x00;
+const ISSI_REG_CONFIG_AUTOPLAYMODE: u8 = 0x08;
+const ISSI_REG_CONFIG_AUDIOPLAYMODE: u8 = 0x18;
+
 const ISSI_REG_PICTUREFRAME: u8 = 0x01;
 
+const ISSI_REG_DISPLAYOPTION: u8 = 0x05;
+const ISSI_REG_DISPLAYOPTION_BLINK: u8 = 0x08;
+
+const ISSI_REG_AUTOPLAY1: u8 = 0x02;
+const ISSI_REG_AUTOPLAY2: u8 = 0x03;
+
+const ISSI_REG_FRAMESTATE: u8 = 0x07;
+const ISSI_REG_FRAMESTATE_INT: u8 = 0x10;
+
 const ISSI_REG_SHUTDOWN: u8 = 0x0A;
 const ISSI_REG_AUDIOSYNC: u8 = 0x06;
+const ISSI_REG_BREATHCTRL1: u8 = 0x08;
+const ISSI_REG_BREATHCTRL2: u8 = 0x09;
+const ISSI_REG_AGC: u8 = 0x0B;
+const ISSI_REG_ADCRATE: u8 = 0x0C;
+
+const ISSI_REG_LEDCTRL: u8 = 0x00;
+const ISSI_REG_BLINK: u8 = 0x12;
 
 const ISSI_COMMANDREGISTER: u8 = 0xFD;
 const ISSI_BANK_FUNCTIONREG: u8 = 0x0B;
 
-pub struct IS31FL3731<A, T>
-where
-    A: AddressMode + Copy,
-    T: Write<A>,
-{
-    a: A,
-    i2c: T,
+/// function registers written again by reset, the mode goes last so auto play starts with
+/// everything else in place
+const RESTORED_FUNCTION_REGISTERS: [u8; 10] = [
+    ISSI_REG_PICTUREFRAME,
+    ISSI_REG_AUTOPLAY1,
+    ISSI_REG_AUTOPLAY2,
+    ISSI_REG_DISPLAYOPTION,
+    ISSI_REG_AUDIOSYNC,
+    ISSI_REG_BREATHCTRL1,
+    ISSI_REG_BREATHCTRL2,
+    ISSI_REG_AGC,
+    ISSI_REG_ADCRATE,
+    ISSI_REG_CONFIG,
+];
+
+/// length of one autoplay frame delay step (τ in the datasheet)
+const AUTOPLAY_DELAY_STEP_MS: u16 = 11;
+
+/// clean PWM registers between two dirty ones that are cheaper to resend than starting a new
+/// burst, which costs an I2C start, the device address and a register address
+/// only registers the driver owns are resent, the others keep whatever the chip shows
+const BURST_MERGE_GAP: usize = 2;
+
+/// length of one audio ADC sample period step
+const ADC_RATE_STEP_US: u16 = 46;
+
+/// gain added by each AGC gain step
+const AGC_GAIN_STEP_DB: u8 = 3;
+
+/// length of one blink period step
+const BLINK_PERIOD_STEP_MS: u16 = 270;
+
+/// shortest fade in / fade out time, each step doubles it
+const BREATH_FADE_BASE_US: u32 = 26_000;
+
+/// shortest extinguish time, each step doubles it
+const BREATH_EXTINGUISH_BASE_US: u32 = 3_500;
+
+/// pick the step n (0 - 7) whose time base * 2^n is closest to the requested time
+/// like steps, a time closer to the step past the last one is rejected
+fn closest_doubling_step<E>(ms: u16, base_us: u32) -> Result<u8, Error<E>> {
+    let us = ms as u32 * 1000;
+    match (0..=8u8).min_by_key(|&n| (base_us << n).abs_diff(us)) {
+        Some(step) if step < 8 => Ok(step),
+        _ => Err(Error::InvalidConfig),
+    }
+}
+
+/// Clockwise rotation of everything drawn, for displays mounted sideways or upside down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rotation {
+    #[default]
+    Deg0,
+    Deg90,
+    Deg180,
+    Deg270,
+}
+
+/// I2C address of the chip, chosen by what its AD pin is connected to
+///
+/// new and the builder only take these, so the driver can not talk to any other address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Address {
+    /// AD connected to GND, 0x74
+    #[default]
+    Gnd = 0x74,
+    /// AD connected to SCL, 0x75
+    Scl = 0x75,
+    /// AD connected to SDA, 0x76
+    Sda = 0x76,
+    /// AD connected to VCC, 0x77
+    Vcc = 0x77,
+}
+
+/// A 7-bit address the chip can not be set to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidAddress(pub u8);
+
+impl From<Address> for SevenBitAddress {
+    fn from(address: Address) -> Self {
+        address as u8
+    }
+}
+
+impl TryFrom<u8> for Address {
+    type Error = InvalidAddress;
+
+    fn try_from(address: u8) -> Result<Self, Self::Error> {
+        match address {
+            0x74 => Ok(Address::Gnd),
+            0x75 => Ok(Address::Scl),
+            0x76 => Ok(Address::Sda),
+            0x77 => Ok(Address::Vcc),
+            _ => Err(InvalidAddress(address)),
+        }
+    }
+}
+
+/// Errors returned by the driver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error<E> {
+    /// the I2C bus returned an error
+    I2c(E),
+    /// a frame number was not in 0 - 7
+    InvalidFrame,
+    /// a pixel was outside of the display
+    OutOfBounds,
+    /// a configuration value can not be represented by the chip,
+    /// or a mapping returned an LED number past 143
+    InvalidConfig,
+}
+
+fn check_frame<E>(frame: u8) -> Result<(), Error<E>> {
+    if frame > 7 {
+        Err(Error::InvalidFrame)
+    } else {
+        Ok(())
+    }
+}
+
+fn check_led<E>(pixel_num: u8) -> Result<u8, Error<E>> {
+    if pixel_num < 144 {
+        Ok(pixel_num)
+    } else {
+        Err(Error::InvalidConfig)
+    }
+}
+
+/// round a value to the nearest multiple of step, and make sure it is in 1 - max steps
+fn steps<E>(value: u16, step: u16, max: u16) -> Result<u16, Error<E>> {
+    let steps = value.saturating_add(step / 2) / step;
+    if (1..=max).contains(&steps) {
+        Ok(steps)
+    } else {
+        Err(Error::InvalidConfig)
+    }
+}
+
+/// Picture Mode, the chip shows the frame chosen with display_frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Picture;
+
+/// Auto Frame Play mode, the chip plays frames in a loop without any help from the MCU
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AutoPlay;
+
+/// Audio Frame Play mode, the chip picks the displayed frame from the level of the audio input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AudioPlay;
+
+/// What the builder puts the chip in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DisplayMode {
+    Picture(u8),
+    AutoPlay(AutoPlayConfig),
+    AudioPlay,
+}
+
+impl DisplayMode {
+    /// function registers that switch the chip to this mode, in the order to write them
+    fn registers<E>(&self) -> Result<[Option<(u8, u8)>; 3], Error<E>> {
+        Ok(match self {
+            DisplayMode::Picture(frame) => {
+                check_frame(*frame)?;
+                [
+                    Some((ISSI_REG_CONFIG, ISSI_REG_CONFIG_PICTUREMODE)),
+                    Some((ISSI_REG_PICTUREFRAME, *frame)),
+                    None,
+                ]
+            }
+            DisplayMode::AutoPlay(config) => {
+                let [control1, control2] = config.control_registers()?;
+                // writing the mode last starts the animation with the new settings
+                [
+                    Some((ISSI_REG_AUTOPLAY1, control1)),
+                    Some((ISSI_REG_AUTOPLAY2, control2)),
+                    Some((ISSI_REG_CONFIG, config.config_register()?)),
+                ]
+            }
+            DisplayMode::AudioPlay => [
+                Some((ISSI_REG_CONFIG, ISSI_REG_CONFIG_AUDIOPLAYMODE)),
+                None,
+                None,
+            ],
+        })
+    }
+}
+
+/// Settings for Auto Frame Play mode, where the chip cycles through frames on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AutoPlayConfig {
+    /// frame the animation starts on (0 - 7)
+    pub start_frame: u8,
+    /// number of frames to play (1 - 8)
+    pub frame_count: u8,
+    /// number of times to play the animation (1 - 7), 0 loops forever
+    pub loops: u8,
+    /// time each frame is shown, rounded to the nearest 11ms step (11 - 704ms)
+    pub frame_delay_ms: u16,
+}
+
+impl Default for AutoPlayConfig {
+    fn default() -> Self {
+        Self {
+            start_frame: 0,
+            frame_count: 8,
+            loops: 0,
+            frame_delay_ms: 110,
+        }
+    }
+}
+
+impl AutoPlayConfig {
+    fn config_register<E>(&self) -> Result<u8, Error<E>> {
+        check_frame(self.start_frame)?;
+        Ok(ISSI_REG_CONFIG_AUTOPLAYMODE | self.start_frame)
+    }
+
+    fn control_registers<E>(&self) -> Result<[u8; 2], Error<E>> {
+        if !(1..=8).contains(&self.frame_count) || self.loops > 7 {
+            return Err(Error::InvalidConfig);
+        }
+        // a frame count of 8 is written as 0
+        let frames = self.frame_count & 0x07;
+        // a delay of 64 steps is written as 0
+        let delay = steps(self.frame_delay_ms, AUTOPLAY_DELAY_STEP_MS, 64)? as u8 & 0x3f;
+        Ok([self.loops << 4 | frames, delay])
+    }
+}
+
+/// How quickly the automatic gain control reacts to the audio input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AgcMode {
+    #[default]
+    Slow,
+    Fast,
+}
+
+/// Settings for the automatic gain control applied to the audio input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AgcConfig {
+    pub enabled: bool,
+    pub mode: AgcMode,
+    /// input gain, rounded to the nearest 3dB step (0 - 21dB)
+    pub gain_db: u8,
+}
+
+impl AgcConfig {
+    fn register<E>(&self) -> Result<u8, Error<E>> {
+        let mut reg = self.gain_db.saturating_add(AGC_GAIN_STEP_DB / 2) / AGC_GAIN_STEP_DB;
+        if reg > 7 {
+            return Err(Error::InvalidConfig);
+        }
+        if self.enabled {
+            reg |= 0x08;
+        }
+        if self.mode == AgcMode::Fast {
+            reg |= 0x10;
+        }
+        Ok(reg)
+    }
+}
+
+/// Settings for the hardware breathing effect, which fades LEDs in and out
+/// whenever the displayed frame changes
+///
+/// times are rounded to the closest step the chip supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BreathConfig {
+    pub enabled: bool,
+    /// 26ms doubled for each step (26 - 3328ms)
+    pub fade_in_ms: u16,
+    /// 26ms doubled for each step (26 - 3328ms)
+    pub fade_out_ms: u16,
+    /// time spent off between fade out and fade in, 3.5ms doubled for each step (3.5 - 448ms)
+    pub extinguish_ms: u16,
+}
+
+impl BreathConfig {
+    fn registers<E>(&self) -> Result<[u8; 2], Error<E>> {
+        let fade_in = closest_doubling_step(self.fade_in_ms, BREATH_FADE_BASE_US)?;
+        let fade_out = closest_doubling_step(self.fade_out_ms, BREATH_FADE_BASE_US)?;
+        let mut control2 = closest_doubling_step(self.extinguish_ms, BREATH_EXTINGUISH_BASE_US)?;
+        if self.enabled {
+            control2 |= 0x10;
+        }
+        Ok([fade_out << 4 | fade_in, control2])
+    }
+}
+
+/// Settings for hardware blinking of the LEDs that have their blink bit set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BlinkConfig {
+    pub enabled: bool,
+    /// rounded to the nearest 270ms step (270 - 1890ms), ignored while blinking is disabled
+    pub period_ms: u16,
+}
+
+impl BlinkConfig {
+    fn register<E>(&self) -> Result<u8, Error<E>> {
+        if !self.enabled {
+            return Ok(0);
+        }
+        let period = steps(self.period_ms, BLINK_PERIOD_STEP_MS, 7)? as u8;
+        Ok(ISSI_REG_DISPLAYOPTION_BLINK | period)
+    }
+}
+
+/// Contents of the read-only Frame State register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameState {
+    /// frame currently shown on the display (0 - 7)
+    pub current_frame: u8,
+    /// set once an Auto Frame Play animation has played all of its loops
+    pub interrupt: bool,
+}
+
+/// What the driver knows about the 8 frames of the chip, about 1.7 KB
+///
+/// the driver keeps one inside by default. to keep it off the stack, for example in a
+/// `static`, hand a `&mut` borrow of one to [`IS31FL3731::builder_with_frame_buffer`]
+pub struct FrameBuffer {
+    /// LED enable bits last written to each frame
+    leds: [[u8; 18]; 8],
+    /// blink bits last written to each frame
+    blink: [[u8; 18]; 8],
+    /// PWM values of each frame, as drawn
+    pwm: [[u8; 144]; 8],
+    /// PWM values that have not been sent yet, one bit per LED of each frame
+    dirty: [[u8; 18]; 8],
+    /// PWM values drawn or cleared by the driver, the others belong to whatever wrote the
+    /// chip before, such as a bootloader
+    owned: [[u8; 18]; 8],
+}
+
+impl FrameBuffer {
+    pub const fn new() -> Self {
+        Self {
+            leds: [[0xff; 18]; 8],
+            blink: [[0; 18]; 8],
+            pwm: [[0; 144]; 8],
+            dirty: [[0; 18]; 8],
+            owned: [[0; 18]; 8],
+        }
+    }
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Driver state shared by the blocking and async drivers
+struct State<M, B = FrameBuffer> {
+    mapping: M,
+    /// register bank the command register points at, if known
+    bank: Option<u8>,
     current_frame: u8,
+    /// values last written to the function registers 0x00 - 0x0C
+    function: [u8; 13],
+    /// function registers with a known value, one bit per register
+    function_known: u16,
+    frames: B,
+    /// only send PWM values to the chip on flush
+    buffered: bool,
+    /// curve PWM values are sent through
+    gamma: Option<&'static [u8; 256]>,
+    /// every PWM value is scaled by brightness / 255
+    brightness: u8,
+    rotation: Rotation,
+    /// flip drawing left to right
+    mirror_x: bool,
+    /// flip drawing top to bottom
+    mirror_y: bool,
 }
 
-impl<A, T> IS31FL3731<A, T>
-where
-    A: AddressMode + Copy,
-    T: Write<A>,
-{
-    pub fn select_frame(&mut self, frame: u8) {
-        if frame > 7 {
-            self.current_frame = 0;
-        } else {
-            self.current_frame = frame;
+impl<M, B: BorrowMut<FrameBuffer>> State<M, B> {
+    fn new(mapping: M, frames: B) -> Self {
+        Self {
+            mapping,
+            bank: None,
+            current_frame: 0,
+            function: [0; 13],
+            function_known: 0,
+            frames,
+            buffered: false,
+            gamma: None,
+            brightness: 255,
+            rotation: Rotation::Deg0,
+            mirror_x: false,
+            mirror_y: false,
         }
     }
 
-    fn write_to_bank(
-        &mut self,
-        bank: u8,
-        reg: u8,
-        value: u8,
-    ) -> Result<(), <T as Write<A>>::Error> {
-        self.select_bank(bank)?;
-        self.i2c.write(self.a, &[reg, value])
+    fn select_frame<E>(&mut self, frame: u8) -> Result<(), Error<E>> {
+        check_frame(frame)?;
+        self.current_frame = frame;
+        Ok(())
     }
 
-    fn select_bank(&mut self, bank: u8) -> Result<(), <T as Write<A>>::Error> {
-        self.i2c.write(self.a, &[ISSI_COMMANDREGISTER, bank])
+    fn rotated_size(&self, size: Size) -> Size {
+        match self.rotation {
+            Rotation::Deg0 | Rotation::Deg180 => size,
+            Rotation::Deg90 | Rotation::Deg270 => Size::new(size.height, size.width),
+        }
     }
 
-    /// enable each LED and turn them all off
-    /// disable blink as well
-    pub fn clear(&mut self) -> Result<(), <T as Write<A>>::Error> {
+    /// turn a pixel as drawn into a pixel of the board's mapping, if it is on the display
+    fn orient(&self, size: Size, point: Point) -> Option<(u32, u32)> {
+        let rotated = self.rotated_size(size);
+        if !Rectangle::new(Point::zero(), rotated).contains(point) {
+            return None;
+        }
+        let (mut x, mut y) = (point.x as u32, point.y as u32);
+        if self.mirror_x {
+            x = rotated.width - 1 - x;
+        }
+        if self.mirror_y {
+            y = rotated.height - 1 - y;
+        }
+        Some(match self.rotation {
+            Rotation::Deg0 => (x, y),
+            Rotation::Deg90 => (size.width - 1 - y, x),
+            Rotation::Deg180 => (size.width - 1 - x, size.height - 1 - y),
+            Rotation::Deg270 => (y, size.height - 1 - x),
+        })
+    }
+
+    fn remember_function(&mut self, reg: u8, value: u8) {
+        self.function[reg as usize] = value;
+        self.function_known |= 1 << reg;
+    }
+
+    /// command that writes the LED control and blink registers of a frame as stored
+    fn masks_command(&self, frame: u8) -> [u8; 37] {
+        let frames = self.frames.borrow();
+        let frame = frame as usize;
+        let mut command = [0u8; 37];
+        command[0] = ISSI_REG_LEDCTRL;
+        command[1..19].copy_from_slice(&frames.leds[frame]);
+        command[19..].copy_from_slice(&frames.blink[frame]);
+        command
+    }
+
+    /// store the LED control and blink registers of a frame, as read from the chip
+    fn set_masks(&mut self, frame: u8, masks: &[u8; 36]) {
+        let frames = self.frames.borrow_mut();
+        frames.leds[frame as usize].copy_from_slice(&masks[..18]);
+        frames.blink[frame as usize].copy_from_slice(&masks[18..]);
+    }
+
+    /// command that enables each LED of the current frame, turns them all off and disables blink
+    fn clear_command(&mut self) -> [u8; 0xb5] {
         // enable LEDs (manually using IS31FL3731's address auto increment)
         let mut command = [0u8; 0xb5]; // number of registers + 1 for first register address
 
@@ -74,99 +520,697 @@ where
             command[1 + i] = 0x00;
         }
 
-        // select the current frame
-        self.select_bank(self.current_frame)?;
-        // send the command
-        self.i2c.write(self.a, &command)?;
+        let frame = self.current_frame as usize;
+        let frames = self.frames.borrow_mut();
+        frames.leds[frame] = [0xff; 18];
+        frames.blink[frame] = [0; 18];
+        frames.pwm[frame] = [0; 144];
+        frames.dirty[frame] = [0; 18];
+        frames.owned[frame] = [0xff; 18];
+        command
+    }
 
-        Ok(())
+    /// store the PWM value of an LED in the current frame
+    fn set_pwm(&mut self, pixel_num: u8, c: u8) {
+        let frame = self.current_frame as usize;
+        let reg = (pixel_num / 8) as usize;
+        let frames = self.frames.borrow_mut();
+        frames.pwm[frame][pixel_num as usize] = c;
+        set_bit(&mut frames.dirty[frame][reg], pixel_num, true);
+        set_bit(&mut frames.owned[frame][reg], pixel_num, true);
     }
 
-    pub fn display_frame(&mut self, mut frame: u8) -> Result<(), <T as Write<A>>::Error> {
-        if frame > 7 {
-            frame = 0;
-        };
-        self.write_to_bank(ISSI_BANK_FUNCTIONREG, ISSI_REG_PICTUREFRAME, frame)
+    /// store the same PWM value for every LED in the current frame
+    fn fill_pwm(&mut self, c: u8) {
+        let frame = self.current_frame as usize;
+        let frames = self.frames.borrow_mut();
+        frames.pwm[frame] = [c; 144];
+        frames.dirty[frame] = [0xff; 18];
+        frames.owned[frame] = [0xff; 18];
     }
 
-    pub fn new(i2c: T, a: A, d: &mut dyn DelayMs<u8>) -> Result<Self, <T as Write<A>>::Error> {
-        let mut dev = Self {
-            a,
-            i2c,
-            current_frame: 0,
-        };
+    /// send every PWM value the driver owns again on the next flush
+    fn redraw_owned(&mut self) {
+        let frames = self.frames.borrow_mut();
+        for (dirty, owned) in frames.dirty.iter_mut().zip(&frames.owned) {
+            for (dirty, owned) in dirty.iter_mut().zip(owned) {
+                *dirty |= owned;
+            }
+        }
+    }
 
-        // reset
-        dev.write_to_bank(ISSI_BANK_FUNCTIONREG, ISSI_REG_SHUTDOWN, 0x00)?;
-        d.delay_ms(10);
-        dev.write_to_bank(ISSI_BANK_FUNCTIONREG, ISSI_REG_SHUTDOWN, 0x01)?;
+    /// find the next range of LEDs in a frame that needs to be flushed, starting at LED `from`
+    /// the range ends before the first LED the driver does not own
+    fn next_dirty_run(&self, frame: u8, from: usize) -> Option<Range<usize>> {
+        let bit = |bits: &[u8; 18], led: usize| bits[led / 8] & (1 << (led % 8)) != 0;
+        let frames = self.frames.borrow();
+        let is_dirty = |led: usize| bit(&frames.dirty[frame as usize], led);
+        let is_owned = |led: usize| bit(&frames.owned[frame as usize], led);
+        let start = (from..144).find(|&led| is_dirty(led))?;
+        let mut end = start + 1;
+        let mut led = end;
+        while led < 144 && led - end <= BURST_MERGE_GAP && is_owned(led) {
+            if is_dirty(led) {
+                end = led + 1;
+            }
+            led += 1;
+        }
+        Some(start..end)
+    }
 
-        dev.clear()?;
+    /// mark every PWM value of a frame as sent
+    fn flushed(&mut self, frame: u8) {
+        self.frames.borrow_mut().dirty[frame as usize] = [0; 18];
+    }
 
-        for f in 0..8u8 {
-            for i in 0..0x12u8 {
-                dev.write_to_bank(f, i, 0xff)?;
-            }
+    /// PWM value actually sent for a drawn value, after brightness and gamma
+    fn output(&self, c: u8) -> u8 {
+        let c = ((c as u16 * self.brightness as u16 + 127) / 255) as u8;
+        match self.gamma {
+            Some(gamma) => gamma[c as usize],
+            None => c,
         }
+    }
 
-        // disable audio sync
-        dev.write_to_bank(ISSI_BANK_FUNCTIONREG, ISSI_REG_AUDIOSYNC, 0x0)?;
+    /// command that writes the PWM values of a range of LEDs in a frame, and its length
+    fn pwm_command(&self, frame: u8, leds: Range<usize>) -> ([u8; 145], usize) {
+        let mut command = [0u8; 145];
+        command[0] = 0x24 + leds.start as u8;
+        let len = leds.len();
+        for (out, &c) in command[1..=len]
+            .iter_mut()
+            .zip(&self.frames.borrow().pwm[frame as usize][leds])
+        {
+            *out = self.output(c);
+        }
+        (command, len + 1)
+    }
 
-        Ok(dev)
+    /// update the blink bit of an LED in the current frame, returns the register to write
+    fn set_blink(&mut self, pixel_num: u8, blink: bool) -> (u8, u8) {
+        let reg = (pixel_num / 8) as usize;
+        let bits = &mut self.frames.borrow_mut().blink[self.current_frame as usize][reg];
+        set_bit(bits, pixel_num, blink);
+        (ISSI_REG_BLINK + reg as u8, *bits)
     }
 
-    pub fn fill(&mut self, c: u8) -> Result<(), <T as Write<A>>::Error> {
-        let mut command = [c; 145];
-        command[0] = 0x24;
-        self.select_bank(self.current_frame)?;
-        self.i2c.write(self.a, &command)
+    /// update the enable bit of an LED in the current frame, returns the register to write
+    fn set_led_enabled(&mut self, pixel_num: u8, enabled: bool) -> (u8, u8) {
+        let reg = (pixel_num / 8) as usize;
+        let bits = &mut self.frames.borrow_mut().leds[self.current_frame as usize][reg];
+        set_bit(bits, pixel_num, enabled);
+        (ISSI_REG_LEDCTRL + reg as u8, *bits)
     }
 
-    pub fn draw_pixel(
-        &mut self,
-        mut x: i16,
-        mut y: i16,
-        c: u8,
-    ) -> Result<(), <T as Write<A>>::Error> {
-        if x > 7 {
-            x = 15 - x;
-            y += 8;
-        } else {
-            y = 7 - y;
+    /// command that writes every LED enable bit of the current frame
+    fn leds_command(&self) -> [u8; 19] {
+        let mut command = [0u8; 19];
+        command[0] = ISSI_REG_LEDCTRL;
+        command[1..].copy_from_slice(&self.frames.borrow().leds[self.current_frame as usize]);
+        command
+    }
+
+    /// store the LED enable bits of the current frame
+    fn set_leds(&mut self, leds: [u8; 18]) {
+        self.frames.borrow_mut().leds[self.current_frame as usize] = leds;
+    }
+}
+
+impl<M: PixelMapping, B: BorrowMut<FrameBuffer>> State<M, B> {
+    /// size of the display as drawn on, after rotation
+    fn size(&self) -> Size {
+        self.rotated_size(self.mapping.size())
+    }
+
+    /// LED number of a pixel, if it is on the display
+    fn led_index<E>(&self, x: i16, y: i16) -> Result<u8, Error<E>> {
+        self.led_at(Point::new(x.into(), y.into()))
+    }
+
+    /// LED number of a point drawn with embedded-graphics
+    fn led_at<E>(&self, point: Point) -> Result<u8, Error<E>> {
+        let (x, y) = self
+            .orient(self.mapping.size(), point)
+            .ok_or(Error::OutOfBounds)?;
+        check_led(self.mapping.led_index(x, y))
+    }
+
+    /// update the enable bit of every LED in a row of the current frame
+    fn set_row_enabled<E>(&mut self, y: i16, enabled: bool) -> Result<(), Error<E>> {
+        for x in 0..self.size().width as i16 {
+            let pixel_num = self.led_index(x, y)?;
+            self.set_led_enabled(pixel_num, enabled);
         }
-        let t = x;
-        x = y;
-        y = t;
-        let pixel_num = x + y * 16;
-        //let pixel_num = x;
-        self.write_to_bank(self.current_frame, 0x24 + pixel_num as u8, c)
+        Ok(())
     }
 }
 
-impl<A, T> DrawTarget for IS31FL3731<A, T>
-where
-    A: AddressMode + Copy,
-    T: Write<A>,
-{
+impl<M: PixelMapping, B: BorrowMut<FrameBuffer>> StorePixel for State<M, B> {
     type Color = Gray8;
-    type Error = <T as Write<A>>::Error;
-    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
-    where
-        I: IntoIterator<Item = Pixel<Self::Color>>,
-    {
-        for pixel in pixels {
-            self.draw_pixel(pixel.0.x as i16, pixel.0.y as i16, pixel.1.into_storage())?;
+
+    fn store_pixel<E>(&mut self, point: Point, c: Gray8) -> Result<(), Error<E>> {
+        match self.led_at(point) {
+            Ok(pixel_num) => self.set_pwm(pixel_num, c.into_storage()),
+            Err(Error::OutOfBounds) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+}
+
+impl<R: RgbMapping, B: BorrowMut<FrameBuffer>> State<Rgb<R>, B> {
+    /// size of the display as drawn on, after rotation
+    fn size(&self) -> Size {
+        self.rotated_size(self.mapping.0.size())
+    }
+
+    /// LED numbers of the red, green and blue channel of a pixel, if it is on the display
+    fn led_indices<E>(&self, x: i16, y: i16) -> Result<[u8; 3], Error<E>> {
+        self.leds_at(Point::new(x.into(), y.into()))
+    }
+
+    /// LED numbers of a point drawn with embedded-graphics
+    fn leds_at<E>(&self, point: Point) -> Result<[u8; 3], Error<E>> {
+        let (x, y) = self
+            .orient(self.mapping.0.size(), point)
+            .ok_or(Error::OutOfBounds)?;
+        let leds = self.mapping.0.led_indices(x, y);
+        for pixel_num in leds {
+            check_led(pixel_num)?;
+        }
+        Ok(leds)
+    }
+
+    /// update the enable bits of every LED in a row of the current frame
+    fn set_row_enabled<E>(&mut self, y: i16, enabled: bool) -> Result<(), Error<E>> {
+        for x in 0..self.size().width as i16 {
+            for pixel_num in self.led_indices(x, y)? {
+                self.set_led_enabled(pixel_num, enabled);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: RgbMapping, B: BorrowMut<FrameBuffer>> StorePixel for State<Rgb<R>, B> {
+    type Color = Rgb888;
+
+    fn store_pixel<E>(&mut self, point: Point, c: Rgb888) -> Result<(), Error<E>> {
+        match self.leds_at(point) {
+            Ok([r, g, b]) => {
+                self.set_pwm(r, c.r());
+                self.set_pwm(g, c.g());
+                self.set_pwm(b, c.b());
+            }
+            Err(Error::OutOfBounds) => {}
+            Err(e) => return Err(e),
         }
         Ok(())
     }
 }
 
-impl<A, T> OriginDimensions for IS31FL3731<A, T>
+/// Pixels drawn with embedded-graphics, in the color of the mapping
+trait StorePixel {
+    type Color;
+
+    /// store a pixel in the frame buffer, pixels off the display are skipped
+    fn store_pixel<E>(&mut self, point: Point, c: Self::Color) -> Result<(), Error<E>>;
+}
+
+/// set or clear the bit of an LED in its control or blink register
+fn set_bit(bits: &mut u8, pixel_num: u8, on: bool) {
+    if on {
+        *bits |= 1 << (pixel_num % 8);
+    } else {
+        *bits &= !(1 << (pixel_num % 8));
+    }
+}
+
+driver::impl_driver!(blocking);
+
+impl<T, M, S, B> IS31FL3731<T, M, S, B>
 where
-    A: AddressMode + Copy,
-    T: Write<A>,
+    T: I2c,
+    B: BorrowMut<FrameBuffer>,
 {
-    fn size(&self) -> Size {
-        Size::new(15, 7)
+    /// send what was just drawn with embedded-graphics, unless drawing is buffered
+    fn send_drawn(&mut self) -> Result<(), Error<<T as ErrorType>::Error>> {
+        if self.state.buffered {
+            return Ok(());
+        }
+        self.flush_frame(self.state.current_frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use embedded_hal::i2c::{ErrorKind, Operation};
+
+    use super::*;
+
+    /// a mapping with an LED number the chip does not have
+    struct PastLastLed;
+
+    impl PixelMapping for PastLastLed {
+        fn size(&self) -> Size {
+            Size::new(2, 1)
+        }
+
+        fn led_index(&self, x: u32, _y: u32) -> u8 {
+            143 + x as u8
+        }
+    }
+
+    /// a state that owns the LEDs of frame 0 it was given, with nothing left to flush
+    fn owning(leds: &[u8]) -> State<CharlieWing> {
+        let mut state = State::new(CharlieWing, FrameBuffer::new());
+        for &led in leds {
+            state.set_pwm(led, 0);
+        }
+        state.flushed(0);
+        state
+    }
+
+    /// a state that cleared frame 0, so it owns every LED of it
+    fn cleared() -> State<CharlieWing> {
+        let mut state = State::new(CharlieWing, FrameBuffer::new());
+        state.clear_command();
+        state
+    }
+
+    /// check the bursts flush sends for frame 0 with some LEDs changed, as (start, end) pairs
+    fn assert_runs(mut state: State<CharlieWing>, leds: &[u8], runs: &[(usize, usize)]) {
+        for &led in leds {
+            state.set_pwm(led, 1);
+        }
+        let mut from = 0;
+        for &(start, end) in runs {
+            assert_eq!(state.next_dirty_run(0, from), Some(start..end));
+            from = end;
+        }
+        assert_eq!(state.next_dirty_run(0, from), None);
+    }
+
+    #[test]
+    fn dirty_runs() {
+        assert_runs(cleared(), &[], &[]);
+        assert_runs(cleared(), &[0, 3, 7, 8, 143], &[(0, 4), (7, 9), (143, 144)]);
+        // two clean LEDs are merged into the burst, three start a new one
+        assert_runs(cleared(), &[10, 13], &[(10, 14)]);
+        assert_runs(cleared(), &[10, 14], &[(10, 11), (14, 15)]);
+        assert_runs(cleared(), &[142, 143], &[(142, 144)]);
+        // LEDs the driver does not own keep what the chip shows, as after attach
+        assert_runs(
+            State::new(CharlieWing, FrameBuffer::new()),
+            &[10, 13],
+            &[(10, 11), (13, 14)],
+        );
+        assert_runs(owning(&[11]), &[10, 12], &[(10, 13)]);
+        assert_runs(owning(&[11]), &[10, 13], &[(10, 11), (13, 14)]);
+        assert_runs(owning(&[11, 12]), &[10, 13], &[(10, 14)]);
+    }
+
+    #[test]
+    fn filled_frame_is_one_run() {
+        let mut state = State::new(CharlieWing, FrameBuffer::new());
+        state.fill_pwm(1);
+        assert_eq!(state.next_dirty_run(0, 0), Some(0..144));
+        assert_eq!(state.next_dirty_run(1, 0), None);
+    }
+
+    type Corners = [(u32, u32); 4];
+
+    /// for each rotation, the size as drawn and where the corners of a 15x7 display end up
+    /// in the mapping: top left, top right, bottom left, bottom right as drawn
+    const CORNERS: [(Rotation, Size, Corners); 4] = [
+        (
+            Rotation::Deg0,
+            Size::new(15, 7),
+            [(0, 0), (14, 0), (0, 6), (14, 6)],
+        ),
+        (
+            Rotation::Deg90,
+            Size::new(7, 15),
+            [(14, 0), (14, 6), (0, 0), (0, 6)],
+        ),
+        (
+            Rotation::Deg180,
+            Size::new(15, 7),
+            [(14, 6), (0, 6), (14, 0), (0, 0)],
+        ),
+        (
+            Rotation::Deg270,
+            Size::new(7, 15),
+            [(0, 6), (0, 0), (14, 6), (14, 0)],
+        ),
+    ];
+
+    #[test]
+    fn orient_corners() {
+        let mut state = State::new(CharlieWing, FrameBuffer::new());
+        let mapping = CharlieWing.size();
+        for (rotation, size, corners) in CORNERS {
+            for (mirror_x, mirror_y) in [(false, false), (true, false), (false, true), (true, true)]
+            {
+                state.rotation = rotation;
+                state.mirror_x = mirror_x;
+                state.mirror_y = mirror_y;
+                assert_eq!(state.size(), size);
+                let (right, bottom) = (size.width as i32 - 1, size.height as i32 - 1);
+                let drawn = [(0, 0), (right, 0), (0, bottom), (right, bottom)];
+                for (corner, (x, y)) in drawn.into_iter().enumerate() {
+                    // mirroring swaps the corners left to right and top to bottom
+                    let expected = corner ^ (mirror_x as usize) ^ ((mirror_y as usize) << 1);
+                    assert_eq!(
+                        state.orient(mapping, Point::new(x, y)),
+                        Some(corners[expected]),
+                        "{rotation:?} mirrored {mirror_x} {mirror_y}, ({x}, {y})"
+                    );
+                }
+                for (x, y) in [(-1, 0), (0, -1), (right + 1, 0), (0, bottom + 1)] {
+                    assert_eq!(state.orient(mapping, Point::new(x, y)), None);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn auto_play_registers() {
+        let config = AutoPlayConfig::default();
+        // 8 frames are written as 0
+        assert_eq!(config.control_registers::<()>(), Ok([0x00, 0x0a]));
+        assert_eq!(config.config_register::<()>(), Ok(0x08));
+
+        let config = AutoPlayConfig {
+            start_frame: 2,
+            frame_count: 3,
+            loops: 7,
+            // 64 steps are written as 0
+            frame_delay_ms: 704,
+        };
+        assert_eq!(config.control_registers::<()>(), Ok([0x73, 0x00]));
+        assert_eq!(config.config_register::<()>(), Ok(0x0a));
+    }
+
+    #[test]
+    fn auto_play_limits() {
+        let valid = AutoPlayConfig::default();
+        for config in [
+            AutoPlayConfig {
+                frame_count: 0,
+                ..valid
+            },
+            AutoPlayConfig {
+                frame_count: 9,
+                ..valid
+            },
+            AutoPlayConfig { loops: 8, ..valid },
+            AutoPlayConfig {
+                frame_delay_ms: 5,
+                ..valid
+            },
+            AutoPlayConfig {
+                frame_delay_ms: 710,
+                ..valid
+            },
+        ] {
+            assert_eq!(
+                config.control_registers::<()>(),
+                Err(Error::InvalidConfig),
+                "{config:?}"
+            );
+        }
+        let config = AutoPlayConfig {
+            start_frame: 8,
+            ..valid
+        };
+        assert_eq!(config.config_register::<()>(), Err(Error::InvalidFrame));
+    }
+
+    #[test]
+    fn agc_register() {
+        assert_eq!(AgcConfig::default().register::<()>(), Ok(0x00));
+        let config = AgcConfig {
+            enabled: true,
+            mode: AgcMode::Fast,
+            gain_db: 21,
+        };
+        assert_eq!(config.register::<()>(), Ok(0x1f));
+        // gains are rounded to the nearest 3dB step
+        let config = AgcConfig {
+            enabled: true,
+            mode: AgcMode::Slow,
+            gain_db: 4,
+        };
+        assert_eq!(config.register::<()>(), Ok(0x09));
+        let config = AgcConfig {
+            gain_db: 23,
+            ..config
+        };
+        assert_eq!(config.register::<()>(), Err(Error::InvalidConfig));
+    }
+
+    #[test]
+    fn breath_registers() {
+        assert_eq!(BreathConfig::default().registers::<()>(), Ok([0x00, 0x00]));
+        let config = BreathConfig {
+            enabled: true,
+            fade_in_ms: 26,
+            fade_out_ms: 3328,
+            extinguish_ms: 448,
+        };
+        assert_eq!(config.registers::<()>(), Ok([0x70, 0x17]));
+        // times are rounded to the closest doubling step
+        let config = BreathConfig {
+            enabled: false,
+            fade_in_ms: 100,
+            fade_out_ms: 1000,
+            extinguish_ms: 10,
+        };
+        assert_eq!(config.registers::<()>(), Ok([0x52, 0x01]));
+    }
+
+    #[test]
+    fn breath_limits() {
+        // up to half a step past the longest time still rounds down to it
+        let config = BreathConfig {
+            enabled: true,
+            fade_in_ms: 4992,
+            fade_out_ms: 0,
+            extinguish_ms: 672,
+        };
+        assert_eq!(config.registers::<()>(), Ok([0x07, 0x17]));
+        for config in [
+            BreathConfig {
+                fade_in_ms: 4993,
+                ..config
+            },
+            BreathConfig {
+                fade_out_ms: 60_000,
+                ..config
+            },
+            BreathConfig {
+                extinguish_ms: 673,
+                ..config
+            },
+        ] {
+            assert_eq!(
+                config.registers::<()>(),
+                Err(Error::InvalidConfig),
+                "{config:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn blink_register() {
+        assert_eq!(BlinkConfig::default().register::<()>(), Ok(0));
+        let config = BlinkConfig {
+            enabled: true,
+            period_ms: 540,
+        };
+        assert_eq!(config.register::<()>(), Ok(0x0a));
+        let config = BlinkConfig {
+            enabled: true,
+            period_ms: 0,
+        };
+        assert_eq!(config.register::<()>(), Err(Error::InvalidConfig));
+    }
+
+    #[test]
+    fn led_past_143_is_rejected() {
+        let mut state = State::new(PastLastLed, FrameBuffer::new());
+        assert_eq!(state.led_index::<()>(0, 0), Ok(143));
+        assert_eq!(state.led_index::<()>(1, 0), Err(Error::InvalidConfig));
+        assert_eq!(state.led_index::<()>(2, 0), Err(Error::OutOfBounds));
+        assert_eq!(
+            state.store_pixel::<()>(Point::new(1, 0), Gray8::new(1)),
+            Err(Error::InvalidConfig)
+        );
+        assert_eq!(
+            state.store_pixel::<()>(Point::new(2, 0), Gray8::new(1)),
+            Ok(())
+        );
+    }
+
+    /// a bus that records every write, reads give zeros, the next transfer fails while `fail`
+    #[derive(Default)]
+    struct Bus {
+        writes: Vec<Vec<u8>>,
+        fail: bool,
+    }
+
+    impl ErrorType for Bus {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for Bus {
+        fn transaction(
+            &mut self,
+            _address: u8,
+            ops: &mut [Operation<'_>],
+        ) -> Result<(), ErrorKind> {
+            if core::mem::take(&mut self.fail) {
+                return Err(ErrorKind::Other);
+            }
+            for op in ops {
+                match op {
+                    Operation::Write(bytes) => self.writes.push(bytes.to_vec()),
+                    Operation::Read(buffer) => buffer.fill(0),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayNs for NoDelay {
+        fn delay_ns(&mut self, _ns: u32) {}
+    }
+
+    fn select(bank: u8) -> Vec<u8> {
+        vec![ISSI_COMMANDREGISTER, bank]
+    }
+
+    /// a driver set up by new, with the writes that took forgotten
+    fn driver() -> IS31FL3731<Bus> {
+        let mut dev = IS31FL3731::new(Bus::default(), Address::Gnd, &mut NoDelay).unwrap();
+        dev.i2c.writes.clear();
+        dev
+    }
+
+    #[test]
+    fn bank_is_selected_once() {
+        let mut dev = driver();
+        dev.invalidate_bank_cache();
+        dev.write_to_bank(3, 0x24, 1).unwrap();
+        dev.write_to_bank(3, 0x25, 2).unwrap();
+        dev.invalidate_bank_cache();
+        dev.write_to_bank(3, 0x26, 3).unwrap();
+        assert_eq!(
+            dev.i2c.writes,
+            [
+                select(3),
+                vec![0x24, 1],
+                vec![0x25, 2],
+                select(3),
+                vec![0x26, 3]
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_select_selects_again() {
+        let mut dev = driver();
+        dev.write_to_bank(3, 0x24, 1).unwrap();
+        // the select of bank 4 may have reached the chip, so bank 3 is no longer known
+        dev.i2c.fail = true;
+        assert_eq!(
+            dev.write_to_bank(4, 0x24, 2),
+            Err(Error::I2c(ErrorKind::Other))
+        );
+        dev.write_to_bank(3, 0x25, 3).unwrap();
+        assert_eq!(
+            dev.i2c.writes,
+            [select(3), vec![0x24, 1], select(3), vec![0x25, 3]]
+        );
+    }
+
+    #[test]
+    fn builder_sends_reset_clear_leds_and_mode() {
+        let mut expected = vec![
+            select(ISSI_BANK_FUNCTIONREG),
+            vec![ISSI_REG_SHUTDOWN, 0],
+            vec![ISSI_REG_SHUTDOWN, 1],
+        ];
+        let mut clear = vec![0; 0xb5];
+        clear[1..19].fill(0xff);
+        for frame in 0..8 {
+            expected.extend([select(frame), clear.clone()]);
+        }
+        let mut leds = vec![0xff; 19];
+        leds[0] = ISSI_REG_LEDCTRL;
+        for frame in 0..8 {
+            expected.extend([select(frame), leds.clone()]);
+        }
+        expected.extend([
+            select(ISSI_BANK_FUNCTIONREG),
+            vec![ISSI_REG_AUDIOSYNC, 0],
+            vec![ISSI_REG_CONFIG, ISSI_REG_CONFIG_PICTUREMODE],
+            vec![ISSI_REG_PICTUREFRAME, 0],
+        ]);
+        let mut bus = Bus::default();
+        IS31FL3731::new(&mut bus, Address::Gnd, &mut NoDelay).unwrap();
+        assert_eq!(bus.writes, expected);
+    }
+
+    #[test]
+    fn attach_only_reads() {
+        let mut bus = Bus::default();
+        IS31FL3731::builder(&mut bus, Address::Gnd)
+            .attach(true)
+            .auto_play(AutoPlayConfig::default())
+            .build(&mut NoDelay)
+            .unwrap();
+        let expected: Vec<_> = (0..8)
+            .flat_map(|frame| [select(frame), vec![ISSI_REG_LEDCTRL]])
+            .collect();
+        assert_eq!(bus.writes, expected);
+    }
+
+    #[test]
+    fn reset_sends_masks_then_pwm_then_function_registers() {
+        let mut dev = driver();
+        dev.set_breath(&BreathConfig::default()).unwrap();
+        dev.i2c.writes.clear();
+        dev.reset(&mut NoDelay).unwrap();
+
+        let mut expected = vec![
+            select(ISSI_BANK_FUNCTIONREG),
+            vec![ISSI_REG_SHUTDOWN, 0],
+            vec![ISSI_REG_SHUTDOWN, 1],
+        ];
+        let mut masks = vec![0; 37];
+        masks[1..19].fill(0xff);
+        // new cleared every frame, so the driver owns all of their LEDs
+        let mut pwm = vec![0; 145];
+        pwm[0] = 0x24;
+        for frame in 0..8 {
+            expected.extend([select(frame), masks.clone(), pwm.clone()]);
+        }
+        expected.extend([
+            select(ISSI_BANK_FUNCTIONREG),
+            vec![ISSI_REG_PICTUREFRAME, 0],
+            vec![ISSI_REG_AUDIOSYNC, 0],
+            vec![ISSI_REG_BREATHCTRL1, 0],
+            vec![ISSI_REG_BREATHCTRL2, 0],
+            vec![ISSI_REG_CONFIG, ISSI_REG_CONFIG_PICTUREMODE],
+        ]);
+        assert_eq!(dev.i2c.writes, expected);
     }
 }
@@ -0,0 +1,292 @@
+//! How the pixels of a board are wired to the LED outputs of the chip
+
+use embedded_graphics_core::prelude::Size;
+
+/// Maps the logical pixels of a board to the 144 LED outputs of the IS31FL3731
+///
+/// LEDs are numbered in the same order as the PWM registers, 16 per row of the chip's matrix
+pub trait PixelMapping {
+    /// logical size of the display
+    fn size(&self) -> Size;
+
+    /// LED number (0 - 143) of the pixel at (x, y), which is always inside `size()`
+    fn led_index(&self, x: u32, y: u32) -> u8;
+}
+
+/// Adafruit 15x7 CharliePlex LED Matrix FeatherWing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CharlieWing;
+
+impl PixelMapping for CharlieWing {
+    fn size(&self) -> Size {
+        Size::new(15, 7)
+    }
+
+    fn led_index(&self, x: u32, y: u32) -> u8 {
+        let (x, y) = if x > 7 { (15 - x, y + 8) } else { (x, 7 - y) };
+        (y + x * 16) as u8
+    }
+}
+
+/// Adafruit 16x9 CharliePlex LED Matrix, wired in the chip's own order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Matrix16x9;
+
+impl PixelMapping for Matrix16x9 {
+    fn size(&self) -> Size {
+        Size::new(16, 9)
+    }
+
+    fn led_index(&self, x: u32, y: u32) -> u8 {
+        (x + y * 16) as u8
+    }
+}
+
+/// Pimoroni Scroll pHAT HD, 17x7
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ScrollPhatHd;
+
+impl PixelMapping for ScrollPhatHd {
+    fn size(&self) -> Size {
+        Size::new(17, 7)
+    }
+
+    fn led_index(&self, x: u32, y: u32) -> u8 {
+        // columns 0 - 8 use the lower 8 LEDs of each row of the chip, columns 9 - 16 the upper 8
+        if x <= 8 {
+            ((8 - x) * 16 + 6 - y) as u8
+        } else {
+            ((x - 8) * 16 + y - 8) as u8
+        }
+    }
+}
+
+/// Pimoroni 11x7 LED Matrix Breakout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Matrix11x7;
+
+impl PixelMapping for Matrix11x7 {
+    fn size(&self) -> Size {
+        Size::new(11, 7)
+    }
+
+    fn led_index(&self, x: u32, y: u32) -> u8 {
+        // columns 0 - 5 use the lower 8 LEDs of each row of the chip, columns 6 - 10 the upper 8
+        if x <= 5 {
+            (x * 16 + 6 - y) as u8
+        } else {
+            ((x - 6) * 16 + 8 + 6 - y) as u8
+        }
+    }
+}
+
+/// Maps the logical pixels of a board with RGB LEDs to the LED outputs driving their channels
+pub trait RgbMapping {
+    /// logical size of the display
+    fn size(&self) -> Size;
+
+    /// LED numbers (0 - 143) of the red, green and blue channel of the pixel at (x, y),
+    /// which is always inside `size()`
+    fn led_indices(&self, x: u32, y: u32) -> [u8; 3];
+}
+
+/// Wraps an [`RgbMapping`] so the driver draws `Rgb888` colors instead of `Gray8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rgb<M>(pub M);
+
+/// Pimoroni LED SHIM, 28x1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LedShim;
+
+const LED_SHIM: [[u8; 3]; 28] = [
+    [118, 69, 85],
+    [117, 68, 101],
+    [116, 84, 100],
+    [115, 83, 99],
+    [114, 82, 98],
+    [113, 81, 97],
+    [112, 80, 96],
+    [134, 21, 37],
+    [133, 20, 36],
+    [132, 19, 35],
+    [131, 18, 34],
+    [130, 17, 50],
+    [129, 33, 49],
+    [128, 32, 48],
+    [127, 47, 63],
+    [121, 41, 57],
+    [122, 25, 58],
+    [123, 26, 42],
+    [124, 27, 43],
+    [125, 28, 44],
+    [126, 29, 45],
+    [15, 95, 111],
+    [8, 89, 105],
+    [9, 90, 106],
+    [10, 91, 107],
+    [11, 92, 108],
+    [12, 76, 109],
+    [13, 77, 93],
+];
+
+impl RgbMapping for LedShim {
+    fn size(&self) -> Size {
+        Size::new(28, 1)
+    }
+
+    fn led_indices(&self, x: u32, _y: u32) -> [u8; 3] {
+        LED_SHIM[x as usize]
+    }
+}
+
+/// Pimoroni 5x5 RGB Matrix Breakout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rgb5x5;
+
+const RGB_5X5: [[u8; 3]; 25] = [
+    [118, 69, 85],
+    [117, 68, 101],
+    [116, 84, 100],
+    [115, 83, 99],
+    [114, 82, 98],
+    [132, 19, 35],
+    [133, 20, 36],
+    [134, 21, 37],
+    [112, 80, 96],
+    [113, 81, 97],
+    [131, 18, 34],
+    [130, 17, 50],
+    [129, 33, 49],
+    [128, 32, 48],
+    [127, 47, 63],
+    [125, 28, 44],
+    [124, 27, 43],
+    [123, 26, 42],
+    [122, 25, 58],
+    [121, 41, 57],
+    [126, 29, 45],
+    [15, 95, 111],
+    [8, 89, 105],
+    [9, 90, 106],
+    [10, 91, 107],
+];
+
+impl RgbMapping for Rgb5x5 {
+    fn size(&self) -> Size {
+        Size::new(5, 5)
+    }
+
+    fn led_indices(&self, x: u32, y: u32) -> [u8; 3] {
+        RGB_5X5[(x + y * 5) as usize]
+    }
+}
+
+/// Pimoroni Keybow 2040, one pixel per key in its 4x4 grid
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Keybow2040;
+
+const KEYBOW_2040: [[u8; 3]; 16] = [
+    [120, 88, 104],
+    [136, 40, 72],
+    [112, 80, 96],
+    [128, 32, 64],
+    [121, 89, 105],
+    [137, 41, 73],
+    [113, 81, 97],
+    [129, 33, 65],
+    [122, 90, 106],
+    [138, 25, 74],
+    [114, 82, 98],
+    [130, 17, 66],
+    [123, 91, 107],
+    [139, 26, 75],
+    [115, 83, 99],
+    [131, 18, 67],
+];
+
+impl RgbMapping for Keybow2040 {
+    fn size(&self) -> Size {
+        Size::new(4, 4)
+    }
+
+    fn led_indices(&self, x: u32, y: u32) -> [u8; 3] {
+        // keys are numbered column by column, starting at the right
+        KEYBOW_2040[((3 - x) * 4 + y) as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// every pixel has to drive its own LED, and only LEDs that exist
+    fn assert_unique(mapping: impl PixelMapping) {
+        let size = mapping.size();
+        let mut used = [false; 144];
+        for y in 0..size.height {
+            for x in 0..size.width {
+                let led = mapping.led_index(x, y) as usize;
+                assert!(led < 144, "({x}, {y}) maps to LED {led}");
+                assert!(
+                    !used[led],
+                    "({x}, {y}) maps to LED {led}, which is already used"
+                );
+                used[led] = true;
+            }
+        }
+    }
+
+    /// every channel of every pixel has to drive its own LED, and only LEDs that exist
+    fn assert_rgb_unique(mapping: impl RgbMapping) {
+        let size = mapping.size();
+        let mut used = [false; 144];
+        for y in 0..size.height {
+            for x in 0..size.width {
+                for led in mapping.led_indices(x, y) {
+                    let led = led as usize;
+                    assert!(led < 144, "({x}, {y}) maps to LED {led}");
+                    assert!(
+                        !used[led],
+                        "({x}, {y}) maps to LED {led}, which is already used"
+                    );
+                    used[led] = true;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn charlie_wing_is_unique() {
+        assert_unique(CharlieWing);
+    }
+
+    #[test]
+    fn matrix_16x9_is_unique() {
+        assert_unique(Matrix16x9);
+    }
+
+    #[test]
+    fn scroll_phat_hd_is_unique() {
+        assert_unique(ScrollPhatHd);
+    }
+
+    #[test]
+    fn matrix_11x7_is_unique() {
+        assert_unique(Matrix11x7);
+    }
+
+    #[test]
+    fn led_shim_is_unique() {
+        assert_rgb_unique(LedShim);
+    }
+
+    #[test]
+    fn rgb_5x5_is_unique() {
+        assert_rgb_unique(Rgb5x5);
+    }
+
+    #[test]
+    fn keybow_2040_is_unique() {
+        assert_rgb_unique(Keybow2040);
+    }
+}